@@ -0,0 +1,130 @@
+//! Shared wire framing for the broadcast handlers ([`crate::tx_handler`] and
+//! [`crate::cache_update_handler`]).
+//!
+//! Every broadcast or subscription frame on either socket, in either
+//! direction, starts with the same fixed header, so a client can tell which
+//! stream it's on, reject a version it doesn't understand, demultiplex
+//! message kinds without guessing, and resume a dropped stream from a known
+//! point:
+//!
+//! ```text
+//! +----------------+-----------+-------------+------------+----------------+-----...-----+
+//! | magic (4 bytes)| version(1)| msg type (1)| seq (8 BE) | body len (4 BE)|    body     |
+//! +----------------+-----------+-------------+------------+----------------+-----...-----+
+//! ```
+//!
+//! This header is an in-memory envelope, not a second layer of I/O:
+//! [`encode_frame`] and [`decode_frame`] encode/parse one whole buffer rather
+//! than driving their own reads off a stream. Outbound broadcast frames
+//! (`TxEffectsAndEvents`/`CacheObjectUpdate`/`GapNotice`) are written by
+//! `broadcast_transport::ConnectionWriter` as the encoded frame alone on
+//! Unix/TCP (the header's body length delimits it) or as one message on
+//! WebSocket. Inbound subscription frames a client sends to declare or update
+//! its filter ([`MessageType::Subscription`]) carry the same header, but
+//! `broadcast_transport::ConnectionReader` reads each one as a chunk: on
+//! Unix/TCP the frame is preceded by a `u32` Big Endian length of the whole
+//! frame, on WebSocket it is one message. The optional auth token a
+//! TCP/WebSocket client sends first is read the same way but is raw token
+//! bytes with no frame header.
+//!
+//! The body itself keeps each handler's existing per-kind encoding (bincode,
+//! bcs, json); this module only standardizes the envelope around it.
+//!
+//! `seq` is the monotonically increasing sequence number of the broadcast
+//! message this frame carries. [`MessageType::GapNotice`] and
+//! [`MessageType::Subscription`] are control frames with no real sequence, so
+//! they always use the reserved value [`NO_SEQUENCE`] (`0`), since stream
+//! sequence numbers start above that.
+
+use anyhow::Result;
+
+/// Identifies this as a sui broadcast frame, not an unrelated protocol.
+pub const FRAME_MAGIC: [u8; 4] = *b"SUIB";
+/// Bumped whenever the envelope or a message type's body encoding changes in
+/// a way older clients can't parse. Bumped to 2 when the sequence number
+/// field was added to the header.
+pub const FRAME_VERSION: u8 = 2;
+/// magic (4) + version (1) + message type (1) + seq (8) + body length (4).
+pub const FRAME_HEADER_LEN: usize = 18;
+/// `seq` value used for control frames that don't correspond to a position in
+/// the broadcast stream (e.g. [`MessageType::GapNotice`],
+/// [`MessageType::Subscription`]). Real sequence numbers are always nonzero
+/// (seeded from wall-clock time, never below 1), so this can't collide with
+/// one.
+pub const NO_SEQUENCE: u64 = 0;
+
+/// Which handler/payload a frame carries. New kinds are added here rather
+/// than inferred from which socket a client connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// Body is `[u32 BE effects len][bincode effects][u32 BE events len][json events]`.
+    TxEffectsAndEvents = 0,
+    /// Body is `[u32 LE objects len][bcs objects]`.
+    CacheObjectUpdate = 1,
+    /// Empty body. Sent instead of a replay when a reconnecting client's
+    /// last-seen sequence number has already fallen out of the replay ring;
+    /// the client must fall back to a full resync (reconnect with no
+    /// resume point).
+    GapNotice = 2,
+    /// Sent by the client, not the server: body is a bcs-encoded filter
+    /// (`TxFilter`/`CacheFilter`, handler-specific). First frame after
+    /// connecting, and re-sendable at any point afterwards to replace it.
+    Subscription = 3,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::TxEffectsAndEvents),
+            1 => Some(Self::CacheObjectUpdate),
+            2 => Some(Self::GapNotice),
+            3 => Some(Self::Subscription),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes `body` behind the standard frame header, tagged with `seq`.
+pub fn encode_frame(message_type: MessageType, seq: u64, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + body.len());
+    frame.extend_from_slice(&FRAME_MAGIC);
+    frame.push(FRAME_VERSION);
+    frame.push(message_type as u8);
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Parses a complete frame (header + body) already in hand, e.g. one chunk
+/// returned by `ConnectionReader::read_framed`. Rejects an unrecognized
+/// magic, an unsupported version, or a body length that doesn't match what
+/// was actually delivered.
+pub fn decode_frame(raw: &[u8]) -> Result<(MessageType, u64, &[u8])> {
+    anyhow::ensure!(
+        raw.len() >= FRAME_HEADER_LEN,
+        "frame of {} bytes is shorter than the {FRAME_HEADER_LEN} byte header",
+        raw.len()
+    );
+    anyhow::ensure!(raw[0..4] == FRAME_MAGIC, "not a sui broadcast frame");
+
+    let version = raw[4];
+    anyhow::ensure!(
+        version == FRAME_VERSION,
+        "unsupported broadcast frame version {version} (expected {FRAME_VERSION})"
+    );
+
+    let message_type = MessageType::from_u8(raw[5])
+        .ok_or_else(|| anyhow::anyhow!("unknown broadcast message type {}", raw[5]))?;
+    let seq = u64::from_be_bytes(raw[6..14].try_into().expect("slice is 8 bytes"));
+    let body_len = u32::from_be_bytes(raw[14..18].try_into().expect("slice is 4 bytes")) as usize;
+
+    anyhow::ensure!(
+        raw.len() == FRAME_HEADER_LEN + body_len,
+        "frame declares a body of {body_len} bytes but {} bytes were delivered",
+        raw.len() - FRAME_HEADER_LEN
+    );
+
+    Ok((message_type, seq, &raw[FRAME_HEADER_LEN..]))
+}
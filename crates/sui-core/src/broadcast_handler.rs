@@ -0,0 +1,543 @@
+//! Generic core shared by the broadcast handlers ([`crate::tx_handler`] and
+//! [`crate::cache_update_handler`]).
+//!
+//! The two handlers differ only in what they broadcast (transaction effects
+//! and events vs. object updates) and in how their Unix socket is bound
+//! (`interprocess`'s cross-platform socket vs. `tokio::net::UnixListener`).
+//! Everything else — the connection list, the subscription handshake, the
+//! per-connection reader/writer tasks, the bounded replay ring, the
+//! broadcast loop, and graceful/quick shutdown — was previously duplicated
+//! almost verbatim between the two files. This module holds that shared
+//! logic once, generic over a [`BroadcastPayload`] implementation; each
+//! handler type wraps it with its own public API and its own Unix accept
+//! loop.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::broadcast_framing::{self, MessageType};
+use crate::broadcast_transport::ConnectionReader;
+use crate::broadcast_transport::ConnectionWriter;
+
+/// How many unwritten frames a single lagging connection may buffer before
+/// further frames are dropped for it.
+pub(crate) const CONNECTION_QUEUE_CAPACITY: usize = 1024;
+/// A connection that can't make room for this many consecutive frames is
+/// disconnected by its writer task the next time one of its writes completes.
+/// A connection whose write never completes is not disconnected this way;
+/// further frames for it are only dropped and counted.
+pub(crate) const MAX_CONSECUTIVE_FULL_SENDS: u32 = 16;
+/// A client that doesn't finish the subscription handshake in time is dropped
+/// rather than left holding a slot forever.
+pub(crate) const SUBSCRIPTION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound on a subscription frame's declared length, to avoid letting an
+/// unauthenticated client force a large allocation.
+pub(crate) const MAX_SUBSCRIPTION_FRAME_BYTES: usize = 1 << 20;
+
+/// Everything a broadcast handler needs to know about its payload type to
+/// reuse the connection/broadcast machinery in this module.
+pub(crate) trait BroadcastPayload: Clone + Send + Sync + 'static {
+    /// A client's declared subscription interest, sent as the first frame
+    /// after connecting and re-sendable at any point to replace it. An
+    /// empty filter should match everything, to keep old "subscribe to
+    /// everything" clients working.
+    type Filter: Default + Clone + PartialEq + Send + Sync + 'static + Serialize + DeserializeOwned;
+
+    /// The wire message-type tag this payload's frames carry.
+    const MESSAGE_TYPE: MessageType;
+
+    /// Only meaningful on the connect frame: the last sequence number the
+    /// client saw before disconnecting.
+    fn resume_from(filter: &Self::Filter) -> Option<u64>;
+
+    /// Builds this message's frame body (not yet wrapped in the shared frame
+    /// header), restricted to whatever part of the message matches `filter`.
+    /// Returns `None` if nothing matches (the caller should skip sending a
+    /// frame to this connection) or if serialization failed.
+    fn encode_filtered(&self, filter: &Self::Filter) -> Option<Vec<u8>>;
+}
+
+/// Liveness counters for a single connection's outbound queue, exposed so
+/// operators can see which subscribers are lagging. Shared between the
+/// connection's producer side (the broadcast loop, which only ever increments
+/// `dropped_frames`/`consecutive_full_sends`) and its own writer task (which
+/// resets `consecutive_full_sends` on a successful write and acts on it once
+/// it crosses `MAX_CONSECUTIVE_FULL_SENDS`), so eviction decisions are made
+/// by the connection's own task rather than by the broadcast hot path.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    pending_bytes: AtomicUsize,
+    dropped_frames: AtomicU64,
+    consecutive_full_sends: AtomicU32,
+}
+
+/// A snapshot of [`ConnectionStats`] for one connection.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStat {
+    pub id: u64,
+    pub pending_bytes: usize,
+    pub dropped_frames: u64,
+}
+
+/// One client connection's outbound side: a bounded queue feeding its own
+/// writer task, so a slow client can never block the broadcast loop or other
+/// connections. The connection's read half is polled independently so the
+/// client can refine its subscription filter mid-session. The connection may
+/// have arrived over any configured transport; [`ConnectionReader`]/
+/// [`ConnectionWriter`] erase that once the handshake is done.
+pub(crate) struct Connection<P: BroadcastPayload> {
+    id: u64,
+    frame_tx: mpsc::Sender<Arc<[u8]>>,
+    filter: Arc<Mutex<P::Filter>>,
+    writer_task: JoinHandle<()>,
+    reader_task: JoinHandle<()>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl<P: BroadcastPayload> std::fmt::Debug for Connection<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// The pieces a handler's own Unix accept loop needs, alongside this
+/// module's generic TCP/WebSocket accept loops and broadcast loop. Built
+/// once in a handler's `new_with_transports` and cloned out to whichever
+/// accept loops are spun up.
+pub(crate) struct SharedState<P: BroadcastPayload> {
+    pub(crate) conns: Arc<Mutex<Vec<Connection<P>>>>,
+    pub(crate) next_conn_id: Arc<AtomicU64>,
+    pub(crate) next_seq: Arc<AtomicU64>,
+    pub(crate) replay_ring: Arc<Mutex<VecDeque<(u64, P)>>>,
+    pub(crate) shutdown: CancellationToken,
+}
+
+impl<P: BroadcastPayload> std::fmt::Debug for SharedState<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedState").finish_non_exhaustive()
+    }
+}
+
+impl<P: BroadcastPayload> SharedState<P> {
+    pub(crate) fn new(replay_capacity: usize) -> Self {
+        Self {
+            conns: Arc::new(Mutex::new(Vec::new())),
+            next_conn_id: Arc::new(AtomicU64::new(1)),
+            next_seq: Arc::new(AtomicU64::new(initial_sequence())),
+            replay_ring: Arc::new(Mutex::new(VecDeque::with_capacity(replay_capacity))),
+            shutdown: CancellationToken::new(),
+        }
+    }
+}
+
+/// Seeds the sequence counter from wall-clock time (milliseconds since the
+/// Unix epoch, left-shifted to leave room for a per-millisecond counter)
+/// instead of always starting at 1. A reconnecting client's last-seen
+/// sequence number from before a crash/redeploy is then, barring a restart
+/// within the same millisecond as the one that produced it, guaranteed to be
+/// less than anything assigned after this restart. Combined with
+/// `handle_new_connection` treating an empty replay ring as a gap whenever a
+/// client asks to resume, this is what turns "process restarted, ring reset
+/// to empty" into a correctly reported gap instead of the client silently
+/// joining the live stream as if nothing happened and then treating every
+/// subsequent (small, post-restart) sequence number as a stale duplicate of
+/// something it already has.
+fn initial_sequence() -> u64 {
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    // Real sequence numbers must stay nonzero (0 is `NO_SEQUENCE`); a
+    // nonzero `millis` already guarantees that for any plausible clock.
+    (millis << 20).max(1)
+}
+
+/// Completes the subscription handshake for a freshly accepted connection
+/// and, if the client cooperates, starts its reader/writer tasks, replays
+/// any buffered messages the client asked to resume from, and adds it to the
+/// live connection list. Transport-agnostic: `reader`/`writer` may have
+/// arrived over the Unix socket, TCP, or WebSocket.
+pub(crate) async fn handle_new_connection<P: BroadcastPayload>(
+    mut reader: ConnectionReader,
+    writer: ConnectionWriter,
+    id: u64,
+    connections: Arc<Mutex<Vec<Connection<P>>>>,
+    replay_ring: Arc<Mutex<VecDeque<(u64, P)>>>,
+) {
+    let filter = match tokio::time::timeout(
+        SUBSCRIPTION_HANDSHAKE_TIMEOUT,
+        read_filter_frame::<P>(&mut reader),
+    )
+    .await
+    {
+        Ok(Ok(filter)) => filter,
+        Ok(Err(e)) => {
+            warn!("connection {id} sent an invalid subscription frame, dropping: {e}");
+            return;
+        }
+        Err(_) => {
+            warn!("connection {id} did not complete the subscription handshake in time, dropping");
+            return;
+        }
+    };
+    let resume_from = P::resume_from(&filter);
+    let filter = Arc::new(Mutex::new(filter));
+
+    let (frame_tx, frame_rx) = mpsc::channel(CONNECTION_QUEUE_CAPACITY);
+    let stats = Arc::new(ConnectionStats::default());
+
+    let writer_task = tokio::spawn(connection_writer_loop(
+        writer,
+        frame_rx,
+        id,
+        connections.clone(),
+        stats.clone(),
+    ));
+    let reader_task = tokio::spawn(connection_reader_loop(
+        reader,
+        id,
+        filter.clone(),
+        connections.clone(),
+    ));
+
+    // Holding the replay ring lock across "compute replay" and "join the
+    // live connection list" makes the two atomic with respect to `dispatch`,
+    // which holds the same lock across "append to ring" and "broadcast to
+    // live connections": whichever of the two runs first completes in full
+    // before the other starts, so this connection never sees a message
+    // twice (once replayed, once broadcast live) or misses one in between.
+    let ring = replay_ring.lock().await;
+
+    if let Some(last_seen) = resume_from {
+        let oldest_buffered_seq = ring.front().map(|(seq, _)| *seq);
+        // An empty ring means either nothing has ever been broadcast, or
+        // (far more likely for a client that has a `last_seen` at all) the
+        // process restarted and the ring reset: either way there's nothing
+        // here to prove no messages were missed, so treat it as a gap
+        // rather than silently joining the client with zero replay.
+        let gap = match oldest_buffered_seq {
+            Some(oldest) => last_seen.saturating_add(1) < oldest,
+            None => true,
+        };
+
+        if gap {
+            warn!(
+                "connection {id} asked to resume from seq {last_seen}, but the replay buffer has nothing to prove it didn't miss anything (empty, or that point has fallen out of it); sending a gap notice"
+            );
+            let gap_frame: Arc<[u8]> =
+                broadcast_framing::encode_frame(MessageType::GapNotice, broadcast_framing::NO_SEQUENCE, &[]).into();
+            if frame_tx.try_send(gap_frame.clone()).is_ok() {
+                stats.pending_bytes.fetch_add(gap_frame.len(), Ordering::SeqCst);
+            }
+        } else {
+            for (seq, message) in ring.iter().filter(|(seq, _)| *seq > last_seen) {
+                let body = {
+                    let filter = filter.lock().await;
+                    message.encode_filtered(&filter)
+                };
+                if let Some(body) = body {
+                    let frame: Arc<[u8]> = broadcast_framing::encode_frame(P::MESSAGE_TYPE, *seq, &body).into();
+                    if frame_tx.try_send(frame.clone()).is_ok() {
+                        stats.pending_bytes.fetch_add(frame.len(), Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+    }
+
+    connections.lock().await.push(Connection {
+        id,
+        frame_tx,
+        filter,
+        writer_task,
+        reader_task,
+        stats,
+    });
+}
+
+/// Reads one framed frame and decodes it as a [`MessageType::Subscription`]
+/// frame carrying a bcs-encoded `P::Filter`.
+async fn read_filter_frame<P: BroadcastPayload>(reader: &mut ConnectionReader) -> Result<P::Filter> {
+    let raw = reader
+        .read_framed(MAX_SUBSCRIPTION_FRAME_BYTES + broadcast_framing::FRAME_HEADER_LEN)
+        .await?;
+    let (message_type, _seq, body) = broadcast_framing::decode_frame(&raw)?;
+    anyhow::ensure!(
+        message_type == MessageType::Subscription,
+        "expected a subscription frame, got {message_type:?}"
+    );
+    Ok(bcs::from_bytes(body)?)
+}
+
+/// Keeps a connection's filter up to date for the lifetime of the
+/// connection; clients may send a new subscription frame at any point to
+/// replace their filter. Exits (and prunes the connection) once the read
+/// side errors or is closed by the client.
+pub(crate) async fn connection_reader_loop<P: BroadcastPayload>(
+    mut reader: ConnectionReader,
+    id: u64,
+    filter: Arc<Mutex<P::Filter>>,
+    connections: Arc<Mutex<Vec<Connection<P>>>>,
+) {
+    loop {
+        match read_filter_frame::<P>(&mut reader).await {
+            Ok(new_filter) => *filter.lock().await = new_filter,
+            Err(e) => {
+                warn!("connection {id} subscription stream closed, disconnecting: {e}");
+                remove_connection(&connections, id).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Per-connection writer task: owns the write side of the connection and
+/// serializes writes for this client alone, so a stalled client can only
+/// ever back up its own queue.
+///
+/// Also owns eviction for lag: the broadcast loop only ever increments
+/// `stats.consecutive_full_sends` when it can't enqueue a frame for this
+/// connection, never acts on it directly (see `send_to_all_connections`).
+/// This task checks and resets that counter after every successful write and
+/// disconnects itself if it had reached `MAX_CONSECUTIVE_FULL_SENDS`, so a
+/// lagging client is pruned from the connection's own task rather than from
+/// the broadcast hot path. The check runs only after a write completes, so a
+/// write that never completes keeps the connection in place.
+pub(crate) async fn connection_writer_loop<P: BroadcastPayload>(
+    mut writer: ConnectionWriter,
+    mut frame_rx: mpsc::Receiver<Arc<[u8]>>,
+    id: u64,
+    connections: Arc<Mutex<Vec<Connection<P>>>>,
+    stats: Arc<ConnectionStats>,
+) {
+    while let Some(frame) = frame_rx.recv().await {
+        let result = writer.write_framed(&frame).await;
+        stats.pending_bytes.fetch_sub(frame.len(), Ordering::SeqCst);
+
+        if let Err(e) = result {
+            warn!("connection {id} write failed, disconnecting: {e}");
+            remove_connection(&connections, id).await;
+            return;
+        }
+
+        if stats.consecutive_full_sends.swap(0, Ordering::SeqCst) >= MAX_CONSECUTIVE_FULL_SENDS {
+            warn!(
+                "connection {id} exceeded {MAX_CONSECUTIVE_FULL_SENDS} consecutive full sends, disconnecting"
+            );
+            remove_connection(&connections, id).await;
+            return;
+        }
+    }
+
+    // Sender side was dropped (graceful shutdown): nothing more to flush.
+    writer.close().await;
+}
+
+/// Removes a connection and stops both of its background tasks. Safe to
+/// call from either task when it detects the connection is dead.
+async fn remove_connection<P: BroadcastPayload>(connections: &Arc<Mutex<Vec<Connection<P>>>>, id: u64) {
+    let mut conns = connections.lock().await;
+    if let Some(pos) = conns.iter().position(|c| c.id == id) {
+        let conn = conns.remove(pos);
+        conn.writer_task.abort();
+        conn.reader_task.abort();
+    }
+}
+
+/// Broadcast task loop: dispatches every message queued by the handler to
+/// the connection list, draining whatever is left in the channel once
+/// cancelled rather than dropping it.
+pub(crate) async fn broadcast_loop<P: BroadcastPayload>(
+    mut receiver: mpsc::UnboundedReceiver<P>,
+    connections: Arc<Mutex<Vec<Connection<P>>>>,
+    replay_ring: Arc<Mutex<VecDeque<(u64, P)>>>,
+    next_seq: Arc<AtomicU64>,
+    replay_capacity: usize,
+    shutdown: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            message = receiver.recv() => {
+                match message {
+                    Some(message) => dispatch(message, &connections, &replay_ring, &next_seq, replay_capacity).await,
+                    None => return,
+                }
+            }
+        }
+    }
+
+    // Cancellation must not drop messages that were already committed and
+    // queued: drain whatever is left before exiting.
+    while let Ok(message) = receiver.try_recv() {
+        dispatch(message, &connections, &replay_ring, &next_seq, replay_capacity).await;
+    }
+}
+
+/// Assigns the next sequence number to `message`, appends it to the bounded
+/// replay ring (evicting the oldest entry if full), and fans it out to every
+/// live connection. The ring append and the fan-out happen under the same
+/// ring lock so a connection joining concurrently (see
+/// `handle_new_connection`) can't see this message twice or miss it.
+async fn dispatch<P: BroadcastPayload>(
+    message: P,
+    connections: &Arc<Mutex<Vec<Connection<P>>>>,
+    replay_ring: &Arc<Mutex<VecDeque<(u64, P)>>>,
+    next_seq: &Arc<AtomicU64>,
+    replay_capacity: usize,
+) {
+    let seq = next_seq.fetch_add(1, Ordering::Relaxed);
+
+    let mut ring = replay_ring.lock().await;
+    ring.push_back((seq, message.clone()));
+    while ring.len() > replay_capacity {
+        ring.pop_front();
+    }
+
+    send_to_all_connections(seq, &message, connections).await;
+}
+
+/// Filters the message per connection against its subscription filter and
+/// fans the resulting frame out to each connection's bounded queue. A
+/// connection with no matching content is skipped entirely, and one that
+/// can't keep up just has frames dropped and counted for it here — this loop
+/// never removes a connection itself; that's left to the connection's own
+/// writer task (see `connection_writer_loop`) so the broadcast hot path never
+/// blocks on a lock acquisition or task abort for someone else's dead
+/// connection.
+///
+/// Most connections keep the default ("subscribe to everything") filter, so
+/// `encode_filtered` would otherwise redo the same bincode/json/bcs work for
+/// every one of them. The default filter's encoding is computed at most once
+/// per message and reused for every connection whose filter still equals it;
+/// only a connection with a genuinely different filter pays for its own
+/// encoding.
+async fn send_to_all_connections<P: BroadcastPayload>(
+    seq: u64,
+    message: &P,
+    connections: &Arc<Mutex<Vec<Connection<P>>>>,
+) {
+    let conns = connections.lock().await;
+
+    let default_filter = P::Filter::default();
+    let mut default_frame: Option<Option<Arc<[u8]>>> = None;
+
+    for conn in conns.iter() {
+        let filter = conn.filter.lock().await;
+        let frame = if *filter == default_filter {
+            default_frame
+                .get_or_insert_with(|| {
+                    message
+                        .encode_filtered(&default_filter)
+                        .map(|body| broadcast_framing::encode_frame(P::MESSAGE_TYPE, seq, &body).into())
+                })
+                .clone()
+        } else {
+            message
+                .encode_filtered(&filter)
+                .map(|body| broadcast_framing::encode_frame(P::MESSAGE_TYPE, seq, &body).into())
+        };
+        drop(filter);
+
+        let Some(frame) = frame else { continue };
+
+        match conn.frame_tx.try_send(frame.clone()) {
+            Ok(()) => {
+                conn.stats.pending_bytes.fetch_add(frame.len(), Ordering::SeqCst);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                conn.stats.dropped_frames.fetch_add(1, Ordering::SeqCst);
+                let streak = conn.stats.consecutive_full_sends.fetch_add(1, Ordering::SeqCst) + 1;
+                warn!("connection {} is lagging, dropped a frame ({streak} in a row)", conn.id);
+            }
+            // The writer task owns removing a closed connection from the
+            // list; if we can still see it here, that's merely in flight.
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Snapshot of per-connection queue depth and drop counts, for operators to
+/// see which subscribers are lagging.
+pub(crate) async fn connection_stats<P: BroadcastPayload>(
+    connections: &Arc<Mutex<Vec<Connection<P>>>>,
+) -> Vec<ConnectionStat> {
+    connections
+        .lock()
+        .await
+        .iter()
+        .map(|c| ConnectionStat {
+            id: c.id,
+            pending_bytes: c.stats.pending_bytes.load(Ordering::SeqCst),
+            dropped_frames: c.stats.dropped_frames.load(Ordering::SeqCst),
+        })
+        .collect()
+}
+
+/// Current connection count. Uses `try_lock` so a caller checking this for
+/// observability never blocks on the connection list.
+pub(crate) fn connection_count<P: BroadcastPayload>(connections: &Arc<Mutex<Vec<Connection<P>>>>) -> usize {
+    connections.try_lock().map(|c| c.len()).unwrap_or(0)
+}
+
+/// Signals every loop to stop and returns immediately; socket cleanup
+/// happens in a detached task. The broadcast loop still drains whatever is
+/// queued in the mpsc channel in the background, but nothing waits for it or
+/// for a slow client's buffered frames to be written, and connections are
+/// not closed. Use [`shutdown_graceful`] instead when in-flight messages
+/// must be flushed before returning.
+pub(crate) fn shutdown_quick(shutdown: &CancellationToken, path: String) {
+    shutdown.cancel();
+    tokio::spawn(async move {
+        let _ = fs::remove_file(&path);
+    });
+}
+
+/// Signals every loop to stop, then waits for the broadcast loop to drain
+/// every message already queued in the mpsc channel and flush it to all live
+/// connections, closes each connection, and removes the socket file before
+/// returning.
+pub(crate) async fn shutdown_graceful<P: BroadcastPayload>(
+    shutdown: &CancellationToken,
+    broadcast_task: &Mutex<Option<JoinHandle<()>>>,
+    accept_tasks: &Mutex<Vec<JoinHandle<()>>>,
+    connections: &Arc<Mutex<Vec<Connection<P>>>>,
+    path: &str,
+) {
+    shutdown.cancel();
+
+    if let Some(task) = broadcast_task.lock().await.take() {
+        let _ = task.await;
+    }
+    for task in accept_tasks.lock().await.drain(..) {
+        let _ = task.await;
+    }
+
+    let conns: Vec<Connection<P>> = connections.lock().await.drain(..).collect();
+    for conn in conns {
+        // The read side has no more use once we're shutting down.
+        conn.reader_task.abort();
+        // Dropping the sender lets the writer task drain whatever is
+        // already buffered for this connection before it exits.
+        drop(conn.frame_tx);
+        let _ = conn.writer_task.await;
+    }
+
+    let _ = fs::remove_file(path);
+}
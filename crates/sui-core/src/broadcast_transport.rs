@@ -0,0 +1,352 @@
+//! Transport-agnostic connection plumbing shared by the broadcast handlers
+//! ([`crate::tx_handler`] and [`crate::cache_update_handler`]).
+//!
+//! Both handlers used to be hard-wired to a single Unix domain socket. To let
+//! remote/browser clients subscribe without a sidecar relay, a handler can
+//! now additionally (or instead) listen on a plain TCP address and/or accept
+//! WebSocket upgrades, all feeding the same broadcast task and connection
+//! list. [`ConnectionReader`]/[`ConnectionWriter`] erase the transport a
+//! connection arrived on behind a uniform framed read/write interface, so the
+//! rest of each handler (subscription handshake, writer loop, stats) doesn't
+//! need to care which one it's talking to.
+//!
+//! **Security note:** unlike the Unix socket (gated by filesystem
+//! permissions), the TCP and WebSocket listeners have no transport
+//! encryption and, unless [`TransportConfig::required_token`] is set, no
+//! authentication at all — anyone who can reach the configured address gets
+//! full read access to whatever the handler broadcasts (transaction effects
+//! and events, or raw object contents including owner addresses). Operators
+//! enabling either transport should bind to a loopback or otherwise
+//! firewalled address and/or set `required_token`, and should put a TLS
+//! terminator in front if the listener is ever reachable from an untrusted
+//! network; `required_token` alone is a shared-secret gate, not encryption.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// How many of the most recently broadcast messages are kept around by
+/// default so a reconnecting client can replay what it missed instead of
+/// silently losing it, when [`TransportConfig::replay_capacity`] isn't
+/// overridden. Older entries are evicted first.
+pub const DEFAULT_REPLAY_CAPACITY: usize = 1024;
+
+/// Upper bound on an auth token frame's declared length, to avoid letting an
+/// unauthenticated client force a large allocation before it's even checked.
+const MAX_AUTH_TOKEN_BYTES: usize = 4096;
+
+/// Which transports a handler should accept connections over. A handler's
+/// existing Unix domain socket (`interprocess` or `tokio::net::UnixListener`,
+/// depending on the handler) is configured separately; this only covers the
+/// transports common to both. Any combination may be enabled at once, each
+/// with its own accept loop.
+///
+/// See the module-level security note before enabling `tcp_addr` or
+/// `websocket_addr` on anything but a loopback/firewalled address.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// Accept raw TCP connections on this address.
+    pub tcp_addr: Option<SocketAddr>,
+    /// Accept WebSocket connections on this address; each broadcast frame is
+    /// sent as one binary WS message.
+    pub websocket_addr: Option<SocketAddr>,
+    /// How many of the most recently broadcast messages the handler keeps
+    /// around for replay. See `DEFAULT_REPLAY_CAPACITY` for the default.
+    pub replay_capacity: usize,
+    /// If set, every TCP/WebSocket connection must present this exact token
+    /// as its first frame (ahead of the subscription frame) before it's
+    /// admitted; a missing or mismatched token drops the connection with no
+    /// response. The Unix socket never requires it, since it's already
+    /// gated by filesystem permissions. This is a minimal shared-secret
+    /// check, not a substitute for TLS — a token sent over plain TCP is
+    /// visible to anyone who can observe the connection.
+    pub required_token: Option<String>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            tcp_addr: None,
+            websocket_addr: None,
+            replay_capacity: DEFAULT_REPLAY_CAPACITY,
+            required_token: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Logs a loud runtime warning, once, for every insecure transport this
+    /// config enables: TCP or WebSocket with no `required_token` set. Meant
+    /// to be called from a handler's `new_with_transports` so an operator
+    /// actually sees this at startup, not just in a doc comment. `handler`
+    /// names the caller (e.g. `"TxHandler"`) so the warning is actionable
+    /// when both handlers are running in the same process.
+    pub fn warn_if_insecure(&self, handler: &str) {
+        if self.required_token.is_some() {
+            return;
+        }
+
+        if let Some(addr) = self.tcp_addr {
+            warn!(
+                "{handler}: tcp broadcast listener on {addr} has no required_token set — \
+                 any client that can reach it gets full read access with no authentication; \
+                 see the broadcast_transport module docs before exposing this beyond loopback"
+            );
+        }
+        if let Some(addr) = self.websocket_addr {
+            warn!(
+                "{handler}: websocket broadcast listener on {addr} has no required_token set — \
+                 any client that can reach it gets full read access with no authentication; \
+                 see the broadcast_transport module docs before exposing this beyond loopback"
+            );
+        }
+    }
+}
+
+/// A newly accepted connection's read half. Unix/TCP are both plain byte
+/// streams and share the `Raw` variant (type-erased so the handlers don't
+/// need to know which one they got); WebSocket is message-framed, so a
+/// message boundary stands in for the length prefix `Raw` streams need.
+pub enum ConnectionReader {
+    Raw(Box<dyn AsyncRead + Send + Unpin>),
+    WebSocket(futures_util::stream::SplitStream<WebSocketStream<TcpStream>>),
+}
+
+/// A newly accepted connection's write half. See [`ConnectionReader`].
+pub enum ConnectionWriter {
+    Raw(Box<dyn AsyncWrite + Send + Unpin>),
+    WebSocket(futures_util::stream::SplitSink<WebSocketStream<TcpStream>, Message>),
+}
+
+impl ConnectionReader {
+    /// Reads one frame: a `u32` Big Endian length prefix followed by that
+    /// many bytes on a `Raw` connection, or one whole binary message on a
+    /// WebSocket connection (ping/pong/text frames are skipped). `max_len`
+    /// bounds the allocation so an untrusted peer can't force a huge buffer
+    /// via a bogus length.
+    pub async fn read_framed(&mut self, max_len: usize) -> Result<Vec<u8>> {
+        match self {
+            ConnectionReader::Raw(reader) => {
+                let mut len_bytes = [0u8; 4];
+                reader.read_exact(&mut len_bytes).await?;
+                let len = u32::from_be_bytes(len_bytes) as usize;
+                anyhow::ensure!(
+                    len <= max_len,
+                    "frame of {len} bytes exceeds the {max_len} byte limit"
+                );
+
+                let mut body = vec![0u8; len];
+                reader.read_exact(&mut body).await?;
+                Ok(body)
+            }
+            ConnectionReader::WebSocket(stream) => loop {
+                let message = stream
+                    .next()
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("websocket closed"))??;
+
+                match message {
+                    Message::Binary(bytes) => {
+                        anyhow::ensure!(
+                            bytes.len() <= max_len,
+                            "frame of {} bytes exceeds the {max_len} byte limit",
+                            bytes.len()
+                        );
+                        return Ok(bytes);
+                    }
+                    Message::Close(_) => anyhow::bail!("websocket closed"),
+                    // Ping/pong/text carry no frame data; keep waiting for
+                    // the next binary message.
+                    _ => continue,
+                }
+            },
+        }
+    }
+}
+
+impl ConnectionWriter {
+    /// Writes one already-encoded frame to the connection: raw bytes for a
+    /// `Raw` connection, or a single binary WebSocket message.
+    pub async fn write_framed(&mut self, frame: &[u8]) -> Result<()> {
+        match self {
+            ConnectionWriter::Raw(writer) => {
+                writer.write_all(frame).await?;
+                Ok(())
+            }
+            ConnectionWriter::WebSocket(sink) => {
+                sink.send(Message::Binary(frame.to_vec())).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Closes the connection. Best-effort: errors are swallowed since this
+    /// only ever runs while a connection is already being torn down.
+    pub async fn close(&mut self) {
+        match self {
+            ConnectionWriter::Raw(writer) => {
+                let _ = writer.shutdown().await;
+            }
+            ConnectionWriter::WebSocket(sink) => {
+                let _ = sink.close().await;
+            }
+        }
+    }
+}
+
+/// Reads one framed token from `reader` and compares it to `required_token`.
+/// Returns `true` if the connection may proceed: either no token is
+/// configured, or the client supplied a matching one. Intended to run once,
+/// immediately after accepting a TCP/WebSocket connection and before the
+/// subscription handshake. See the module-level security note: this is a
+/// best-effort shared-secret gate, not transport encryption.
+///
+/// Bounded by [`crate::broadcast_handler::SUBSCRIPTION_HANDSHAKE_TIMEOUT`], the
+/// same deadline the subscription frame that follows is held to: a client
+/// that never sends a token must not be able to wedge this check forever.
+pub async fn check_auth_token(reader: &mut ConnectionReader, required_token: Option<&str>) -> bool {
+    let Some(expected) = required_token else {
+        return true;
+    };
+
+    let read = tokio::time::timeout(
+        crate::broadcast_handler::SUBSCRIPTION_HANDSHAKE_TIMEOUT,
+        reader.read_framed(MAX_AUTH_TOKEN_BYTES),
+    )
+    .await;
+
+    match read {
+        Ok(Ok(presented)) => presented == expected.as_bytes(),
+        Ok(Err(_)) | Err(_) => false,
+    }
+}
+
+/// Spawns an accept loop for the TCP transport, if configured, calling
+/// `on_accept` with the boxed read/write halves of every connection.
+/// Returns `None` when `config.tcp_addr` isn't set. Binds exactly the
+/// address given with no restriction of its own — see the module-level
+/// security note.
+///
+/// `on_accept` runs in its own spawned task per connection, the same way the
+/// handlers' Unix accept loops already do, rather than inline in this loop's
+/// `select!`: a connection that stalls during `check_auth_token`/the
+/// subscription handshake (or never sends anything at all) must not be able
+/// to block every other client from connecting over this transport.
+pub fn spawn_tcp_accept_loop<F, Fut>(
+    config: &TransportConfig,
+    shutdown: CancellationToken,
+    on_accept: F,
+) -> Option<JoinHandle<()>>
+where
+    F: Fn(ConnectionReader, ConnectionWriter) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let addr = config.tcp_addr?;
+
+    Some(tokio::spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("failed to bind tcp broadcast listener on {addr}: {e}");
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = listener.accept() => {
+                    let stream = match accepted {
+                        Ok((stream, _peer)) => stream,
+                        Err(e) => {
+                            warn!("error accepting tcp broadcast connection: {e}");
+                            continue;
+                        }
+                    };
+
+                    let (read_half, write_half) = tokio::io::split(stream);
+                    tokio::spawn(on_accept(
+                        ConnectionReader::Raw(Box::new(read_half)),
+                        ConnectionWriter::Raw(Box::new(write_half)),
+                    ));
+                }
+            }
+        }
+    }))
+}
+
+/// Spawns an accept loop for the WebSocket transport, if configured, calling
+/// `on_accept` with the split sink/stream of every upgraded connection.
+/// Returns `None` when `config.websocket_addr` isn't set. Binds exactly the
+/// address given with no restriction of its own — see the module-level
+/// security note.
+///
+/// `on_accept` runs in its own spawned task per connection; see
+/// [`spawn_tcp_accept_loop`] for why.
+pub fn spawn_websocket_accept_loop<F, Fut>(
+    config: &TransportConfig,
+    shutdown: CancellationToken,
+    on_accept: F,
+) -> Option<JoinHandle<()>>
+where
+    F: Fn(ConnectionReader, ConnectionWriter) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let addr = config.websocket_addr?;
+    let on_accept = std::sync::Arc::new(on_accept);
+
+    Some(tokio::spawn(async move {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("failed to bind websocket broadcast listener on {addr}: {e}");
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = listener.accept() => {
+                    let tcp_stream = match accepted {
+                        Ok((stream, _peer)) => stream,
+                        Err(e) => {
+                            warn!("error accepting websocket broadcast connection: {e}");
+                            continue;
+                        }
+                    };
+
+                    // The WS upgrade handshake is itself a network round trip
+                    // a slow or malicious peer controls; it must not block
+                    // this accept loop any more than `on_accept` may.
+                    let on_accept = on_accept.clone();
+                    tokio::spawn(async move {
+                        let ws_stream = match tokio_tungstenite::accept_async(tcp_stream).await {
+                            Ok(ws) => ws,
+                            Err(e) => {
+                                warn!("websocket upgrade failed: {e}");
+                                return;
+                            }
+                        };
+
+                        let (sink, stream) = ws_stream.split();
+                        on_accept(
+                            ConnectionReader::WebSocket(stream),
+                            ConnectionWriter::WebSocket(sink),
+                        )
+                        .await;
+                    });
+                }
+            }
+        }
+    }))
+}
@@ -1,38 +1,120 @@
+use std::collections::{HashSet, VecDeque};
 use std::path::PathBuf;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
 use anyhow::Result;
-use sui_types::base_types::ObjectID;
+use serde::{Deserialize, Serialize};
+use sui_types::base_types::{ObjectID, SuiAddress};
 use sui_types::object::Object;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::{Mutex, mpsc};
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, Mutex};
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 use tracing::{error, info, warn};
 
+use crate::broadcast_framing::MessageType;
+use crate::broadcast_handler::{self, BroadcastPayload, Connection, ConnectionStat, SharedState};
+use crate::broadcast_transport::{self, ConnectionReader, ConnectionWriter, TransportConfig};
+
 const SOCKET_PATH: &str = "/tmp/sui/sui_cache_updates.sock";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct CacheBroadcastMessage {
     objects: Vec<(ObjectID, Object)>,
 }
 
+/// A client's declared interest, sent as the first frame after connecting and
+/// updatable at any point afterwards. An empty filter matches every object,
+/// which keeps old "subscribe to everything" clients working.
+///
+/// This is serialized with `bcs`, which is positional and non-self
+/// describing: adding or removing a field is a breaking wire change for any
+/// sender built against the old shape, and `#[serde(default)]` (which only
+/// helps named/map-based formats like JSON) does not change that. A client
+/// and server must agree on this exact shape; compatibility across shape
+/// changes is enforced by `FRAME_VERSION`, not by this struct's annotations.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct CacheFilter {
+    object_ids: HashSet<ObjectID>,
+    owners: HashSet<SuiAddress>,
+    /// Only meaningful on the first (connect) frame: the last sequence
+    /// number this client saw before disconnecting. If set, buffered
+    /// messages with a greater sequence are replayed before the connection
+    /// joins the live broadcast.
+    resume_from: Option<u64>,
+}
+
+impl CacheFilter {
+    fn is_empty(&self) -> bool {
+        self.object_ids.is_empty() && self.owners.is_empty()
+    }
+
+    fn matches(&self, id: &ObjectID, object: &Object) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        if self.object_ids.contains(id) {
+            return true;
+        }
+
+        object
+            .owner
+            .get_owner_address()
+            .is_ok_and(|owner| self.owners.contains(&owner))
+    }
+}
+
+impl BroadcastPayload for CacheBroadcastMessage {
+    type Filter = CacheFilter;
+
+    const MESSAGE_TYPE: MessageType = MessageType::CacheObjectUpdate;
+
+    fn resume_from(filter: &CacheFilter) -> Option<u64> {
+        filter.resume_from
+    }
+
+    /// Builds the frame body: `[u32 LE objects len][bcs objects]`, restricted
+    /// to objects matching `filter`. Returns `None` (skip this connection) if
+    /// nothing matches.
+    fn encode_filtered(&self, filter: &CacheFilter) -> Option<Vec<u8>> {
+        let matching: Vec<&(ObjectID, Object)> = self
+            .objects
+            .iter()
+            .filter(|(id, object)| filter.matches(id, object))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+
+        let serialized = bcs::to_bytes(&matching).ok()?;
+
+        let mut body = Vec::with_capacity(4 + serialized.len());
+        body.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+        body.extend_from_slice(&serialized);
+
+        Some(body)
+    }
+}
+
 /// A handler for managing connections with external cache update clients.
 ///
 /// When it detects that objects related to DeFi protocols or other specific addresses
-/// have been modified, it pushes the updated object data to clients via a Unix socket.
+/// have been modified, it pushes the updated object data to clients. It always listens
+/// on a Unix socket, and can optionally also listen on TCP and/or accept WebSocket
+/// upgrades, per the [`TransportConfig`] passed to
+/// [`CacheUpdateHandler::new_with_transports`]; every transport feeds the same
+/// broadcast task and connection list. The connection/broadcast machinery itself lives
+/// in [`crate::broadcast_handler`], shared with [`crate::tx_handler`].
 #[derive(Debug)]
 pub struct CacheUpdateHandler {
     socket_path: PathBuf,
-    connections: Arc<Mutex<Vec<UnixStream>>>,
-    running: Arc<AtomicBool>,
-    // Message queue sender
+    shared: SharedState<CacheBroadcastMessage>,
     tx_sender: mpsc::UnboundedSender<CacheBroadcastMessage>,
-    // Background task handle
-    _broadcast_task: JoinHandle<()>,
+    accept_tasks: Mutex<Vec<JoinHandle<()>>>,
+    broadcast_task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl CacheUpdateHandler {
@@ -41,7 +123,7 @@ impl CacheUpdateHandler {
         if !socket_path.exists() {
             return false;
         }
-        
+
         // Try to connect to see if someone is listening
         match std::os::unix::net::UnixStream::connect(socket_path) {
             Ok(_) => {
@@ -55,21 +137,34 @@ impl CacheUpdateHandler {
         }
     }
 
+    /// Listens on the Unix socket at the fixed `SOCKET_PATH` only. Equivalent
+    /// to `Self::new_with_transports(TransportConfig::default())`.
     pub fn new() -> Self {
+        Self::new_with_transports(TransportConfig::default())
+    }
+
+    /// Listens on the Unix socket at the fixed `SOCKET_PATH`, plus whichever
+    /// of TCP and WebSocket are configured in `transports`. Every listener
+    /// feeds the same broadcast task and connection list, so a client
+    /// subscribes the same way regardless of which transport it connected
+    /// over.
+    pub fn new_with_transports(transports: TransportConfig) -> Self {
+        transports.warn_if_insecure("CacheUpdateHandler");
+
         let socket_path = PathBuf::from(SOCKET_PATH);
-        
+
         // Ensure the parent directory exists
         if let Some(parent_dir) = socket_path.parent() {
             if let Err(e) = std::fs::create_dir_all(parent_dir) {
                 error!("Failed to create socket directory {:?}: {}", parent_dir, e);
             }
         }
-        
+
         // Check if socket is already in use
         if Self::is_socket_in_use(&socket_path) {
             panic!("Socket {:?} is already in use by another process", socket_path);
         }
-        
+
         // Remove stale socket file if it exists but no one is listening
         if socket_path.exists() {
             info!("Removing stale socket file: {:?}", socket_path);
@@ -77,137 +172,205 @@ impl CacheUpdateHandler {
                 warn!("Failed to remove stale socket file {:?}: {}", socket_path, e);
             }
         }
-        
+
         // Now try to bind
         let listener = UnixListener::bind(&socket_path).unwrap_or_else(|e| {
             panic!("Failed to bind Unix socket at {:?}: {}", socket_path, e);
         });
-        
-        info!("Successfully bound Unix socket at {:?}", socket_path);
 
-        let connections = Arc::new(Mutex::new(Vec::new()));
-        let running = Arc::new(AtomicBool::new(true));
+        info!("Successfully bound Unix socket at {:?}", socket_path);
 
-        // Create message queue
+        let shared = SharedState::<CacheBroadcastMessage>::new(transports.replay_capacity);
         let (tx_sender, tx_receiver) = mpsc::unbounded_channel::<CacheBroadcastMessage>();
 
-        // Start connection accept task
-        let connections_for_accept = connections.clone();
-        let running_for_accept = running.clone();
-        tokio::spawn(async move {
-            Self::accept_connections_loop(listener, connections_for_accept, running_for_accept).await;
-        });
+        let mut accept_tasks = Vec::new();
+
+        // Unix socket accept task (always on, never requires the auth
+        // token: it's already gated by filesystem permissions).
+        accept_tasks.push(tokio::spawn(Self::accept_unix_connections_loop(
+            listener,
+            shared.conns.clone(),
+            shared.next_conn_id.clone(),
+            shared.replay_ring.clone(),
+            shared.shutdown.clone(),
+        )));
+
+        let required_token = transports.required_token.clone();
+
+        // TCP accept task, if configured.
+        let connections_for_tcp = shared.conns.clone();
+        let next_conn_id_for_tcp = shared.next_conn_id.clone();
+        let replay_ring_for_tcp = shared.replay_ring.clone();
+        let required_token_for_tcp = required_token.clone();
+        if let Some(task) = broadcast_transport::spawn_tcp_accept_loop(
+            &transports,
+            shared.shutdown.clone(),
+            move |mut reader, writer| {
+                let connections = connections_for_tcp.clone();
+                let next_conn_id = next_conn_id_for_tcp.clone();
+                let replay_ring = replay_ring_for_tcp.clone();
+                let required_token = required_token_for_tcp.clone();
+                async move {
+                    if !broadcast_transport::check_auth_token(&mut reader, required_token.as_deref()).await {
+                        return;
+                    }
+                    let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                    broadcast_handler::handle_new_connection(reader, writer, id, connections, replay_ring).await;
+                }
+            },
+        ) {
+            accept_tasks.push(task);
+        }
+
+        // WebSocket accept task, if configured.
+        let connections_for_ws = shared.conns.clone();
+        let next_conn_id_for_ws = shared.next_conn_id.clone();
+        let replay_ring_for_ws = shared.replay_ring.clone();
+        let required_token_for_ws = required_token.clone();
+        if let Some(task) = broadcast_transport::spawn_websocket_accept_loop(
+            &transports,
+            shared.shutdown.clone(),
+            move |mut reader, writer| {
+                let connections = connections_for_ws.clone();
+                let next_conn_id = next_conn_id_for_ws.clone();
+                let replay_ring = replay_ring_for_ws.clone();
+                let required_token = required_token_for_ws.clone();
+                async move {
+                    if !broadcast_transport::check_auth_token(&mut reader, required_token.as_deref()).await {
+                        return;
+                    }
+                    let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                    broadcast_handler::handle_new_connection(reader, writer, id, connections, replay_ring).await;
+                }
+            },
+        ) {
+            accept_tasks.push(task);
+        }
 
         // Start broadcast task
-        let connections_for_broadcast = connections.clone();
-        let broadcast_task = tokio::spawn(async move {
-            Self::broadcast_loop(tx_receiver, connections_for_broadcast).await;
-        });
+        let broadcast_task = tokio::spawn(broadcast_handler::broadcast_loop(
+            tx_receiver,
+            shared.conns.clone(),
+            shared.replay_ring.clone(),
+            shared.next_seq.clone(),
+            transports.replay_capacity,
+            shared.shutdown.clone(),
+        ));
 
         Self {
             socket_path,
-            connections,
-            running,
+            shared,
             tx_sender,
-            _broadcast_task: broadcast_task,
+            accept_tasks: Mutex::new(accept_tasks),
+            broadcast_task: Mutex::new(Some(broadcast_task)),
+        }
+    }
+
+    /// Unix socket connection accept loop. The only part of the handshake
+    /// that can't live in `broadcast_handler`: `tokio::net::UnixListener`
+    /// (used here) differs from `interprocess`'s cross-platform `Listener`
+    /// (used by [`crate::tx_handler`]).
+    async fn accept_unix_connections_loop(
+        listener: UnixListener,
+        connections: Arc<Mutex<Vec<Connection<CacheBroadcastMessage>>>>,
+        next_conn_id: Arc<AtomicU64>,
+        replay_ring: Arc<Mutex<VecDeque<(u64, CacheBroadcastMessage)>>>,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, _addr)) => {
+                            let (read_half, write_half) = tokio::io::split(stream);
+                            let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                            tokio::spawn(broadcast_handler::handle_new_connection(
+                                ConnectionReader::Raw(Box::new(read_half)),
+                                ConnectionWriter::Raw(Box::new(write_half)),
+                                id,
+                                connections.clone(),
+                                replay_ring.clone(),
+                            ));
+                        }
+                        Err(e) => {
+                            error!("Error accepting connection: {}", e);
+                        }
+                    }
+                }
+            }
         }
     }
 
     /// Queue message for broadcast
     pub async fn queue_for_broadcast(&self, objects: Vec<(ObjectID, Object)>) -> Result<()> {
-        let message = CacheBroadcastMessage {
-            objects,
-        };
-        
-        self.tx_sender.send(message)
+        let message = CacheBroadcastMessage { objects };
+
+        self.tx_sender
+            .send(message)
             .map_err(|_| anyhow::anyhow!("Broadcast task has stopped"))?;
-        
+
         Ok(())
     }
 
     /// Notifies all connected clients of a set of object updates.
     ///
-    /// This function sends a binary stream over the Unix socket with the following structure:
+    /// Each frame is a [`crate::broadcast_framing`] header of type
+    /// `CacheObjectUpdate`, tagged with the message's sequence number, and
+    /// wrapping a body structured as follows:
     /// 1. Total length of the serialized object list data (4 bytes, Little Endian u32).
     /// 2. The list of objects (`Vec<(ObjectID, Object)>`), serialized using `bcs`.
+    ///
+    /// Only objects matching each connection's subscription filter are
+    /// included, and a connection whose filter matches nothing in this update
+    /// isn't sent a frame at all. Each connection has its own bounded
+    /// outbound queue, so a slow or stuck client only causes updates to be
+    /// dropped for itself. The update is also kept in a bounded replay ring
+    /// (sized by `TransportConfig::replay_capacity`) so a client that
+    /// reconnects with its last-seen sequence number can catch up on what it
+    /// missed.
     /// Maintain compatibility: directly calls queue_for_broadcast
     pub async fn notify_written(&self, objects: Vec<(ObjectID, Object)>) {
         let _ = self.queue_for_broadcast(objects).await;
     }
 
-    /// Connection accept loop
-    async fn accept_connections_loop(
-        listener: UnixListener,
-        connections: Arc<Mutex<Vec<UnixStream>>>,
-        running: Arc<AtomicBool>,
-    ) {
-        while running.load(Ordering::SeqCst) {
-            match listener.accept().await {
-                Ok((stream, _addr)) => {
-                    info!("New client connected to cache update socket");
-                    let mut connections = connections.lock().await;
-                    connections.push(stream);
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);
-                }
-            }
-        }
-    }
-
-    /// Broadcast task loop
-    async fn broadcast_loop(
-        mut receiver: mpsc::UnboundedReceiver<CacheBroadcastMessage>,
-        connections: Arc<Mutex<Vec<UnixStream>>>,
-    ) {
-        while let Some(message) = receiver.recv().await {
-            Self::send_to_all_connections(&message, &connections).await;
-        }
+    /// Signals every loop to stop and returns immediately; socket cleanup
+    /// happens in a detached task. The broadcast loop still drains whatever
+    /// is queued in the mpsc channel in the background, but nothing waits for
+    /// it or for a slow client's buffered frames to be written, and
+    /// connections are not closed. Use `shutdown_graceful` instead when
+    /// in-flight updates must be flushed before returning.
+    pub fn shutdown_quick(&self) {
+        broadcast_handler::shutdown_quick(
+            &self.shared.shutdown,
+            self.socket_path.to_string_lossy().into_owned(),
+        );
     }
 
-    /// Send message to all connections
-    async fn send_to_all_connections(
-        message: &CacheBroadcastMessage,
-        connections: &Arc<Mutex<Vec<UnixStream>>>,
-    ) {
-        // Serialize data
-        let serialized = match bcs::to_bytes(&message.objects) {
-            Ok(bytes) => bytes,
-            Err(_) => return, // Serialization failed, skip this message
-        };
-        
-        let len = serialized.len() as u32;
-        let len_bytes = len.to_le_bytes();
-
-        let mut conns = connections.lock().await;
-        let mut active_conns = Vec::new();
-
-        // Process connections one by one, remove invalid connections
-        while let Some(mut conn) = conns.pop() {
-            let result = Self::send_to_connection(&mut conn, &len_bytes, &serialized).await;
-            if result.is_ok() {
-                active_conns.push(conn);
-            }
-        }
-
-        *conns = active_conns;
-    }
-
-    /// Send message to a single connection
-    async fn send_to_connection(
-        conn: &mut UnixStream,
-        len_bytes: &[u8; 4],
-        serialized: &[u8],
-    ) -> Result<()> {
-        conn.write_all(len_bytes).await?;
-        conn.write_all(serialized).await?;
-        Ok(())
+    /// Signals every loop to stop, then waits for the broadcast loop to drain
+    /// every message already queued in the mpsc channel and flush it to all
+    /// live connections, closes each connection, and removes the socket file
+    /// before returning.
+    pub async fn shutdown_graceful(&self) {
+        broadcast_handler::shutdown_graceful(
+            &self.shared.shutdown,
+            &self.broadcast_task,
+            &self.accept_tasks,
+            &self.shared.conns,
+            &self.socket_path.to_string_lossy(),
+        )
+        .await;
     }
 
     /// Get current connection count
     pub fn connection_count(&self) -> usize {
-        // Note: use try_lock to avoid blocking
-        self.connections.try_lock().map(|c| c.len()).unwrap_or(0)
+        broadcast_handler::connection_count(&self.shared.conns)
+    }
+
+    /// Snapshot of per-connection queue depth and drop counts, for operators
+    /// to see which subscribers are lagging.
+    pub async fn connection_stats(&self) -> Vec<ConnectionStat> {
+        broadcast_handler::connection_stats(&self.shared.conns).await
     }
 }
 
@@ -219,8 +382,12 @@ impl Default for CacheUpdateHandler {
 
 impl Drop for CacheUpdateHandler {
     fn drop(&mut self) {
-        self.running.store(false, Ordering::SeqCst);
-        
+        // Drop can't run async code, so this is best-effort only: it stops the
+        // loops and unlinks the socket file, but does not flush queued updates
+        // or wait for the tasks to exit. Prefer calling `shutdown_graceful`
+        // before dropping whenever a clean handoff matters.
+        self.shared.shutdown.cancel();
+
         // Only remove socket file if it exists and we can verify it's ours
         if self.socket_path.exists() {
             if let Err(e) = std::fs::remove_file(&self.socket_path) {
@@ -1,33 +1,129 @@
-use std::{fs, sync::Arc};
+use std::{
+    collections::{HashSet, VecDeque},
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::Result;
 use interprocess::local_socket::{
-    tokio::{prelude::*, Stream, Listener},
+    tokio::{prelude::*, Listener},
     GenericNamespaced, ListenerOptions,
 };
+use serde::{Deserialize, Serialize};
 use sui_json_rpc_types::SuiEvent;
 use sui_types::effects::TransactionEffects;
-use tokio::{io::AsyncWriteExt, sync::{Mutex, mpsc}, task::JoinHandle};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::broadcast_framing::MessageType;
+use crate::broadcast_handler::{self, BroadcastPayload, Connection, ConnectionStat, SharedState};
+use crate::broadcast_transport::{self, ConnectionReader, ConnectionWriter, TransportConfig};
 
 pub const TX_SOCKET_PATH: &str = "/tmp/sui/sui_tx.sock";
 
-#[derive(Debug)]
-struct BroadcastMessage {
+#[derive(Debug, Clone)]
+struct TxBroadcastMessage {
     effects: TransactionEffects,
     events: Vec<SuiEvent>,
 }
 
-/// A handler that manages connections with external clients over a Unix socket
-/// and broadcasts transaction data to them.
+/// A client's declared interest, sent as the first frame after connecting and
+/// updatable at any point afterwards. An empty filter matches every event,
+/// which keeps old "subscribe to everything" clients working.
+///
+/// This is serialized with `bcs`, which is positional and non-self
+/// describing: adding or removing a field is a breaking wire change for any
+/// sender built against the old shape, and `#[serde(default)]` (which only
+/// helps named/map-based formats like JSON) does not change that. A client
+/// and server must agree on this exact shape; compatibility across shape
+/// changes is enforced by `FRAME_VERSION`, not by this struct's annotations.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TxFilter {
+    /// Exact `type_` strings of events this client wants, e.g.
+    /// `0x2::coin::CoinMetadata`.
+    event_types: HashSet<String>,
+    /// Package/module prefixes of `type_` strings this client wants, e.g.
+    /// `0x2::coin::`.
+    type_prefixes: Vec<String>,
+    /// Only meaningful on the first (connect) frame: the last sequence
+    /// number this client saw before disconnecting. If set, buffered
+    /// messages with a greater sequence are replayed before the connection
+    /// joins the live broadcast.
+    resume_from: Option<u64>,
+}
+
+impl TxFilter {
+    fn is_empty(&self) -> bool {
+        self.event_types.is_empty() && self.type_prefixes.is_empty()
+    }
+
+    fn matches(&self, event: &SuiEvent) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let type_str = event.type_.to_string();
+        self.event_types.contains(&type_str)
+            || self.type_prefixes.iter().any(|p| type_str.starts_with(p.as_str()))
+    }
+}
+
+impl BroadcastPayload for TxBroadcastMessage {
+    type Filter = TxFilter;
+
+    const MESSAGE_TYPE: MessageType = MessageType::TxEffectsAndEvents;
+
+    fn resume_from(filter: &TxFilter) -> Option<u64> {
+        filter.resume_from
+    }
+
+    /// Builds the frame body: `[u32 BE effects len][bincode effects][u32 BE
+    /// events len][json events]`, restricted to events matching `filter`.
+    /// Returns `None` (skip this connection) if a non-empty filter matches
+    /// none of the events; the empty filter gets every message, including
+    /// one with no events at all.
+    fn encode_filtered(&self, filter: &TxFilter) -> Option<Vec<u8>> {
+        let matching_events: Vec<&SuiEvent> = self.events.iter().filter(|e| filter.matches(e)).collect();
+        if matching_events.is_empty() && !filter.is_empty() {
+            return None;
+        }
+
+        let effects_bytes = bincode::serialize(&self.effects).ok()?;
+        let events_bytes = serde_json::to_vec(&matching_events).ok()?;
+
+        let mut body = Vec::with_capacity(4 + effects_bytes.len() + 4 + events_bytes.len());
+        body.extend_from_slice(&(effects_bytes.len() as u32).to_be_bytes());
+        body.extend_from_slice(&effects_bytes);
+        body.extend_from_slice(&(events_bytes.len() as u32).to_be_bytes());
+        body.extend_from_slice(&events_bytes);
+
+        Some(body)
+    }
+}
+
+/// A handler that manages connections with external clients and broadcasts
+/// transaction data to them.
 ///
-/// It spawns a background task upon creation to accept new client connections.
+/// It always listens on a Unix socket (cross-platform via `interprocess`, so
+/// this also covers Windows named pipes) and can optionally also listen on
+/// TCP and/or accept WebSocket upgrades, per the [`TransportConfig`] passed
+/// to [`TxHandler::new_with_transports`]; every transport feeds the same
+/// broadcast task and connection list. It spawns one background accept task
+/// per active listener, plus the broadcast task, upon creation. The
+/// connection/broadcast machinery itself lives in
+/// [`crate::broadcast_handler`], shared with [`crate::cache_update_handler`].
 pub struct TxHandler {
     path: String,
-    conns: Arc<Mutex<Vec<Stream>>>,
-    // Message queue sender
-    tx_sender: mpsc::UnboundedSender<BroadcastMessage>,
-    // Background task handle
-    _broadcast_task: JoinHandle<()>,
+    shared: SharedState<TxBroadcastMessage>,
+    tx_sender: mpsc::UnboundedSender<TxBroadcastMessage>,
+    accept_tasks: Mutex<Vec<JoinHandle<()>>>,
+    broadcast_task: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Default for TxHandler {
@@ -38,12 +134,29 @@ impl Default for TxHandler {
 
 impl Drop for TxHandler {
     fn drop(&mut self) {
+        // Drop can't run async code, so this is best-effort only: it stops the
+        // loops and unlinks the socket file, but does not flush queued messages
+        // or wait for the tasks to exit. Prefer calling `shutdown_graceful`
+        // before dropping whenever a clean handoff matters.
+        self.shared.shutdown.cancel();
         let _ = fs::remove_file(&self.path);
     }
 }
 
 impl TxHandler {
+    /// Listens on the Unix socket at `path` only. Equivalent to
+    /// `Self::new_with_transports(path, TransportConfig::default())`.
     pub fn new(path: &str) -> Self {
+        Self::new_with_transports(path, TransportConfig::default())
+    }
+
+    /// Listens on the Unix socket at `path`, plus whichever of TCP and
+    /// WebSocket are configured in `transports`. Every listener feeds the
+    /// same broadcast task and connection list, so a client subscribes the
+    /// same way regardless of which transport it connected over.
+    pub fn new_with_transports(path: &str, transports: TransportConfig) -> Self {
+        transports.warn_if_insecure("TxHandler");
+
         let _ = fs::remove_file(path);
 
         let name = path
@@ -51,58 +164,159 @@ impl TxHandler {
             .expect("Invalid tx socket path");
         let opts = ListenerOptions::new().name(name);
         let listener = opts.create_tokio().expect("Failed to bind tx socket");
-        let conns = Arc::new(Mutex::new(vec![]));
 
-        // Create message queue
-        let (tx_sender, tx_receiver) = mpsc::unbounded_channel::<BroadcastMessage>();
+        let shared = SharedState::<TxBroadcastMessage>::new(transports.replay_capacity);
+        let (tx_sender, tx_receiver) = mpsc::unbounded_channel::<TxBroadcastMessage>();
+
+        let mut accept_tasks = Vec::new();
+
+        // Unix socket accept task (always on, never requires the auth
+        // token: it's already gated by filesystem permissions).
+        accept_tasks.push(tokio::spawn(Self::accept_unix_connections_loop(
+            listener,
+            shared.conns.clone(),
+            shared.next_conn_id.clone(),
+            shared.replay_ring.clone(),
+            shared.shutdown.clone(),
+        )));
+
+        let required_token = transports.required_token.clone();
 
-        // Start connection accept task
-        let conns_for_accept = conns.clone();
-        tokio::spawn(async move {
-            Self::accept_connections_loop(listener, conns_for_accept).await;
-        });
+        // TCP accept task, if configured.
+        let conns_for_tcp = shared.conns.clone();
+        let next_conn_id_for_tcp = shared.next_conn_id.clone();
+        let replay_ring_for_tcp = shared.replay_ring.clone();
+        let required_token_for_tcp = required_token.clone();
+        if let Some(task) = broadcast_transport::spawn_tcp_accept_loop(
+            &transports,
+            shared.shutdown.clone(),
+            move |mut reader, writer| {
+                let conns = conns_for_tcp.clone();
+                let next_conn_id = next_conn_id_for_tcp.clone();
+                let replay_ring = replay_ring_for_tcp.clone();
+                let required_token = required_token_for_tcp.clone();
+                async move {
+                    if !broadcast_transport::check_auth_token(&mut reader, required_token.as_deref()).await {
+                        return;
+                    }
+                    let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                    broadcast_handler::handle_new_connection(reader, writer, id, conns, replay_ring).await;
+                }
+            },
+        ) {
+            accept_tasks.push(task);
+        }
+
+        // WebSocket accept task, if configured.
+        let conns_for_ws = shared.conns.clone();
+        let next_conn_id_for_ws = shared.next_conn_id.clone();
+        let replay_ring_for_ws = shared.replay_ring.clone();
+        let required_token_for_ws = required_token.clone();
+        if let Some(task) = broadcast_transport::spawn_websocket_accept_loop(
+            &transports,
+            shared.shutdown.clone(),
+            move |mut reader, writer| {
+                let conns = conns_for_ws.clone();
+                let next_conn_id = next_conn_id_for_ws.clone();
+                let replay_ring = replay_ring_for_ws.clone();
+                let required_token = required_token_for_ws.clone();
+                async move {
+                    if !broadcast_transport::check_auth_token(&mut reader, required_token.as_deref()).await {
+                        return;
+                    }
+                    let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                    broadcast_handler::handle_new_connection(reader, writer, id, conns, replay_ring).await;
+                }
+            },
+        ) {
+            accept_tasks.push(task);
+        }
 
         // Start broadcast task
-        let conns_for_broadcast = conns.clone();
-        let broadcast_task = tokio::spawn(async move {
-            Self::broadcast_loop(tx_receiver, conns_for_broadcast).await;
-        });
+        let broadcast_task = tokio::spawn(broadcast_handler::broadcast_loop(
+            tx_receiver,
+            shared.conns.clone(),
+            shared.replay_ring.clone(),
+            shared.next_seq.clone(),
+            transports.replay_capacity,
+            shared.shutdown.clone(),
+        ));
 
         Self {
             path: path.to_string(),
-            conns,
+            shared,
             tx_sender,
-            _broadcast_task: broadcast_task,
+            accept_tasks: Mutex::new(accept_tasks),
+            broadcast_task: Mutex::new(Some(broadcast_task)),
+        }
+    }
+
+    /// Unix socket connection accept loop. The only part of the handshake
+    /// that can't live in `broadcast_handler`: `interprocess`'s `Listener`
+    /// type (used here so this also covers Windows named pipes) differs
+    /// from `tokio::net::UnixListener` (used by
+    /// [`crate::cache_update_handler`]).
+    async fn accept_unix_connections_loop(
+        listener: Listener,
+        connections: Arc<Mutex<Vec<Connection<TxBroadcastMessage>>>>,
+        next_conn_id: Arc<AtomicU64>,
+        replay_ring: Arc<Mutex<VecDeque<(u64, TxBroadcastMessage)>>>,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                accepted = listener.accept() => {
+                    let conn = match accepted {
+                        Ok(c) => c,
+                        _err => {
+                            continue;
+                        }
+                    };
+
+                    let (read_half, write_half) = tokio::io::split(conn);
+                    let id = next_conn_id.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(broadcast_handler::handle_new_connection(
+                        ConnectionReader::Raw(Box::new(read_half)),
+                        ConnectionWriter::Raw(Box::new(write_half)),
+                        id,
+                        connections.clone(),
+                        replay_ring.clone(),
+                    ));
+                }
+            }
         }
     }
 
     /// Queue message for broadcast
-    pub async fn queue_for_broadcast(
-        &self,
-        effects: TransactionEffects,
-        events: Vec<SuiEvent>
-    ) -> Result<()> {
-        let message = BroadcastMessage {
-            effects,
-            events,
-        };
-        
-        self.tx_sender.send(message)
+    pub async fn queue_for_broadcast(&self, effects: TransactionEffects, events: Vec<SuiEvent>) -> Result<()> {
+        let message = TxBroadcastMessage { effects, events };
+
+        self.tx_sender
+            .send(message)
             .map_err(|_| anyhow::anyhow!("Broadcast task has stopped"))?;
-        
+
         Ok(())
     }
 
     /// Sends the transaction effects and a list of events to all connected clients.
     ///
-    /// This function sends data over the Unix socket using a specific binary protocol.
-    /// The data packet is structured as follows:
+    /// Each frame is a [`crate::broadcast_framing`] header of type
+    /// `TxEffectsAndEvents`, tagged with the message's sequence number, and
+    /// wrapping a body structured as follows:
     /// 1. Length of the effects data (4 bytes, Big Endian u32).
     /// 2. The `TransactionEffects` data, serialized using `bincode`.
     /// 3. Length of the events data (4 bytes, Big Endian u32).
     /// 4. The `Vec<SuiEvent>` data, serialized into a JSON array using `serde_json`.
     ///
-    /// This function will also prune any connections that have been disconnected.
+    /// Only events matching each connection's subscription filter are
+    /// included, and a connection whose filter matches nothing in this
+    /// message isn't sent a frame at all. Each connection has its own bounded
+    /// outbound queue, so a slow or stuck client only causes frames to be
+    /// dropped for itself. The message is also kept in a bounded replay ring
+    /// (sized by `TransportConfig::replay_capacity`) so a client that
+    /// reconnects with its last-seen sequence number can catch up on what it
+    /// missed.
     /// Maintain compatibility: directly calls queue_for_broadcast
     pub async fn send_tx_effects_and_events(
         &self,
@@ -112,91 +326,39 @@ impl TxHandler {
         self.queue_for_broadcast(effects.clone(), events).await
     }
 
-    /// Connection accept loop
-    async fn accept_connections_loop(
-        listener: Listener,
-        connections: Arc<Mutex<Vec<Stream>>>,
-    ) {
-        loop {
-            let conn = match listener.accept().await {
-                Ok(c) => c,
-                _err => {
-                    continue;
-                }
-            };
-
-            connections.lock().await.push(conn);
-        }
-    }
-
-    /// Broadcast task loop
-    async fn broadcast_loop(
-        mut receiver: mpsc::UnboundedReceiver<BroadcastMessage>,
-        connections: Arc<Mutex<Vec<Stream>>>,
-    ) {
-        while let Some(message) = receiver.recv().await {
-            Self::send_to_all_connections(&message, &connections).await;
-        }
+    /// Signals every loop to stop and returns immediately; socket cleanup
+    /// happens in a detached task. The broadcast loop still drains whatever
+    /// is queued in the mpsc channel in the background, but nothing waits for
+    /// it or for a slow client's buffered frames to be written, and
+    /// connections are not closed. Use `shutdown_graceful` instead when
+    /// in-flight messages must be flushed before returning.
+    pub fn shutdown_quick(&self) {
+        broadcast_handler::shutdown_quick(&self.shared.shutdown, self.path.clone());
     }
 
-    /// Send message to all connections
-    async fn send_to_all_connections(
-        message: &BroadcastMessage,
-        connections: &Arc<Mutex<Vec<Stream>>>,
-    ) {
-        // Serialize data
-        let effects_bytes = match bincode::serialize(&message.effects) {
-            Ok(bytes) => bytes,
-            Err(_) => return, // Serialization failed, skip this message
-        };
-        
-        let events_bytes = match serde_json::to_vec(&message.events) {
-            Ok(bytes) => bytes,
-            Err(_) => return, // Serialization failed, skip this message
-        };
-
-        let effects_len_bytes = (effects_bytes.len() as u32).to_be_bytes();
-        let events_len_bytes = (events_bytes.len() as u32).to_be_bytes();
-
-        let mut conns = connections.lock().await;
-        let mut active_conns = Vec::new();
-
-        // Process connections one by one, remove invalid connections
-        while let Some(mut conn) = conns.pop() {
-            let result = Self::send_to_connection(
-                &mut conn,
-                &effects_len_bytes,
-                &effects_bytes,
-                &events_len_bytes,
-                &events_bytes,
-            ).await;
-
-            if result.is_ok() {
-                active_conns.push(conn);
-            }
-        }
-
-        *conns = active_conns;
-    }
-
-    /// Send message to a single connection
-    async fn send_to_connection(
-        conn: &mut Stream,
-        effects_len_bytes: &[u8; 4],
-        effects_bytes: &[u8],
-        events_len_bytes: &[u8; 4],
-        events_bytes: &[u8],
-    ) -> Result<()> {
-        conn.write_all(effects_len_bytes).await?;
-        conn.write_all(effects_bytes).await?;
-        conn.write_all(events_len_bytes).await?;
-        conn.write_all(events_bytes).await?;
-        Ok(())
+    /// Signals every loop to stop, then waits for the broadcast loop to drain
+    /// every message already queued in the mpsc channel and flush it to all
+    /// live connections, closes each connection, and removes the socket file
+    /// before returning.
+    pub async fn shutdown_graceful(&self) {
+        broadcast_handler::shutdown_graceful(
+            &self.shared.shutdown,
+            &self.broadcast_task,
+            &self.accept_tasks,
+            &self.shared.conns,
+            &self.path,
+        )
+        .await;
     }
 
     /// Get current connection count
     pub fn connection_count(&self) -> usize {
-        // Note: use try_lock to avoid blocking
-        self.conns.try_lock().map(|c| c.len()).unwrap_or(0)
+        broadcast_handler::connection_count(&self.shared.conns)
+    }
+
+    /// Snapshot of per-connection queue depth and drop counts, for operators
+    /// to see which subscribers are lagging.
+    pub async fn connection_stats(&self) -> Vec<ConnectionStat> {
+        broadcast_handler::connection_stats(&self.shared.conns).await
     }
 }